@@ -0,0 +1,284 @@
+use std::{
+    error::Error,
+    io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use snow::{
+    params::NoiseParams,
+    resolvers::{CryptoResolver, DefaultResolver},
+    Builder, TransportState,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Noise pattern used for the optional end-to-end encryption layer: IK authenticates both
+/// sides with static keys in a single round trip, and ChaChaPoly/BLAKE2s keep this crate
+/// free of an OpenSSL-style TLS dependency.
+const NOISE_PARAMS: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise transport message, including its 16-byte ChaCha20-Poly1305 tag.
+const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PLAINTEXT_LEN: usize = NOISE_MAX_MESSAGE_LEN - TAG_LEN;
+
+/// A freshly generated Noise static keypair for this client.
+pub struct StaticKeypair {
+    pub private: Vec<u8>,
+    pub public: Vec<u8>,
+}
+
+/// Generates a new client static keypair for the Noise_IK handshake.
+pub fn generate_keypair() -> Result<StaticKeypair, Box<dyn Error + Send + Sync>> {
+    let keypair = Builder::new(NOISE_PARAMS.parse::<NoiseParams>()?).generate_keypair()?;
+    Ok(StaticKeypair {
+        private: keypair.private,
+        public: keypair.public,
+    })
+}
+
+/// Rebuilds a client static keypair from a previously persisted private key, deriving the
+/// matching public key. Lets a long-lived private key (loaded from disk, or supplied by an
+/// FFI host) be reused across runs instead of generating (and discarding) a fresh identity
+/// every time, which a server authorizing this client by static key depends on.
+pub fn keypair_from_private(private: Vec<u8>) -> Result<StaticKeypair, Box<dyn Error + Send + Sync>> {
+    let params = NOISE_PARAMS.parse::<NoiseParams>()?;
+    let mut dh = DefaultResolver.resolve_dh(&params.dh).ok_or("no DH implementation available")?;
+    dh.set(&private);
+    Ok(StaticKeypair {
+        public: dh.pubkey().to_vec(),
+        private,
+    })
+}
+
+/// Loads the client static keypair persisted at `path`, generating and persisting a new one
+/// if the file doesn't exist yet, so the same identity key is reused across runs.
+pub async fn load_or_generate_keypair(path: &Path) -> Result<StaticKeypair, Box<dyn Error + Send + Sync>> {
+    match tokio::fs::read(path).await {
+        Ok(private) => keypair_from_private(private),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let keypair = generate_keypair()?;
+            tokio::fs::write(path, &keypair.private).await?;
+            Ok(keypair)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The client's static key and the server's pinned public key, as needed to run the
+/// Noise_IK handshake in [`EncryptedStream::handshake`].
+pub struct EncryptionKeys<'a> {
+    pub client_private_key: &'a [u8],
+    pub server_public_key: &'a [u8],
+}
+
+/// Renders `bytes` as lowercase hex, e.g. for logging a client's static public key.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parses a `--server-key`-style hex string back into raw key bytes.
+pub fn decode_hex(s: &str) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    if s.len() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "hex string must have an even length").into());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err).into())
+        })
+        .collect()
+}
+
+enum ReadState {
+    Idle,
+    ReadingLen { buf: [u8; 2], pos: usize },
+    ReadingBody { buf: Vec<u8>, pos: usize },
+    HaveData { data: Vec<u8>, pos: usize },
+}
+
+enum WriteState {
+    Idle,
+    Writing { framed: Vec<u8>, pos: usize, consumed: usize },
+}
+
+/// Wraps an `AsyncRead + AsyncWrite` transport with an opt-in Noise_IK handshake and
+/// length-prefixed ChaCha20-Poly1305 transport messages, so Wisp traffic stays
+/// confidential even over a transport (PTY, plain WebSocket) that provides no encryption
+/// of its own. Constructed via [`EncryptedStream::handshake`], then typically split with
+/// `tokio::io::split` the same way [`crate::pty::open_pty`] splits a plain PTY.
+pub struct EncryptedStream<S> {
+    inner: S,
+    transport: TransportState,
+    read_state: ReadState,
+    write_state: WriteState,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> EncryptedStream<S> {
+    /// Performs the Noise_IK handshake as the initiator: presents `keys.client_private_key`
+    /// and authenticates the peer against `keys.server_public_key`. On success, `inner` is
+    /// ready for encrypted, length-prefixed transport messages.
+    pub async fn handshake(mut inner: S, keys: &EncryptionKeys<'_>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut state = Builder::new(NOISE_PARAMS.parse::<NoiseParams>()?)
+            .local_private_key(keys.client_private_key)
+            .remote_public_key(keys.server_public_key)
+            .build_initiator()?;
+
+        let mut msg = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+        let len = state.write_message(&[], &mut msg)?;
+        inner.write_u16(len as u16).await?;
+        inner.write_all(&msg[..len]).await?;
+        inner.flush().await?;
+
+        let resp_len = inner.read_u16().await? as usize;
+        let mut resp = vec![0u8; resp_len];
+        inner.read_exact(&mut resp).await?;
+        let mut payload = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+        state.read_message(&resp, &mut payload)?;
+
+        let transport = state.into_transport_mode()?;
+        Ok(Self {
+            inner,
+            transport,
+            read_state: ReadState::Idle,
+            write_state: WriteState::Idle,
+        })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.read_state {
+                ReadState::Idle => {
+                    this.read_state = ReadState::ReadingLen { buf: [0u8; 2], pos: 0 };
+                }
+                ReadState::ReadingLen { buf, pos } => {
+                    while *pos < buf.len() {
+                        let mut rb = ReadBuf::new(&mut buf[*pos..]);
+                        match Pin::new(&mut this.inner).poll_read(cx, &mut rb) {
+                            Poll::Ready(Ok(())) => {
+                                let n = rb.filled().len();
+                                if n == 0 {
+                                    if *pos == 0 {
+                                        return Poll::Ready(Ok(()));
+                                    }
+                                    return Poll::Ready(Err(io::Error::new(
+                                        io::ErrorKind::UnexpectedEof,
+                                        "truncated encrypted frame length",
+                                    )));
+                                }
+                                *pos += n;
+                            }
+                            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+                    let len = u16::from_be_bytes(*buf) as usize;
+                    this.read_state = ReadState::ReadingBody { buf: vec![0u8; len], pos: 0 };
+                }
+                ReadState::ReadingBody { buf, pos } => {
+                    while *pos < buf.len() {
+                        let mut rb = ReadBuf::new(&mut buf[*pos..]);
+                        match Pin::new(&mut this.inner).poll_read(cx, &mut rb) {
+                            Poll::Ready(Ok(())) => {
+                                let n = rb.filled().len();
+                                if n == 0 {
+                                    return Poll::Ready(Err(io::Error::new(
+                                        io::ErrorKind::UnexpectedEof,
+                                        "truncated encrypted frame body",
+                                    )));
+                                }
+                                *pos += n;
+                            }
+                            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+                    let mut plaintext = vec![0u8; buf.len()];
+                    let n = this
+                        .transport
+                        .read_message(buf, &mut plaintext)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    plaintext.truncate(n);
+                    this.read_state = ReadState::HaveData { data: plaintext, pos: 0 };
+                }
+                ReadState::HaveData { data, pos } => {
+                    let remaining = &data[*pos..];
+                    let n = remaining.len().min(out.remaining());
+                    out.put_slice(&remaining[..n]);
+                    *pos += n;
+                    if *pos == data.len() {
+                        this.read_state = ReadState::Idle;
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.write_state {
+                WriteState::Idle => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let chunk_len = buf.len().min(MAX_PLAINTEXT_LEN);
+                    let mut ciphertext = vec![0u8; chunk_len + TAG_LEN];
+                    let n = this
+                        .transport
+                        .write_message(&buf[..chunk_len], &mut ciphertext)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    let mut framed = Vec::with_capacity(2 + n);
+                    framed.extend_from_slice(&(n as u16).to_be_bytes());
+                    framed.extend_from_slice(&ciphertext[..n]);
+                    this.write_state = WriteState::Writing {
+                        framed,
+                        pos: 0,
+                        consumed: chunk_len,
+                    };
+                }
+                WriteState::Writing { framed, pos, consumed } => {
+                    while *pos < framed.len() {
+                        match Pin::new(&mut this.inner).poll_write(cx, &framed[*pos..]) {
+                            Poll::Ready(Ok(0)) => {
+                                return Poll::Ready(Err(io::Error::new(
+                                    io::ErrorKind::WriteZero,
+                                    "failed to write encrypted frame",
+                                )))
+                            }
+                            Poll::Ready(Ok(n)) => *pos += n,
+                            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                    }
+                    let consumed = *consumed;
+                    this.write_state = WriteState::Idle;
+                    return Poll::Ready(Ok(consumed));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.as_mut().poll_write(cx, &[]) {
+            Poll::Ready(Ok(_)) => {}
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
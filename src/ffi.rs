@@ -1,60 +1,124 @@
 use std::{
-    ffi::{c_char, c_int, c_ushort, CStr, CString},
+    error::Error,
+    ffi::{c_char, c_int, c_uint, c_ushort, CStr, CString},
     net::SocketAddr,
     ptr,
+    sync::{Once, OnceLock},
+    time::Duration,
 };
 
 use hyper::Uri;
 use tokio::{
     runtime::Runtime,
-    sync::{
-        mpsc::{unbounded_channel, UnboundedSender},
-        Mutex,
-    },
+    sync::Mutex,
+    task::JoinHandle,
 };
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tun2::{create_as_async, AsyncDevice, Configuration};
 
 use crate::{
+    crypto::{self, EncryptionKeys, StaticKeypair},
+    init_logger,
+    stats::StreamRegistry,
     start_whisper,
     util::{connect_to_wisp, WhisperError, WhisperMux},
-    WhisperEvent, WispServer,
+    LogLevel, WispServer,
 };
 
+static LOGGER_INIT: Once = Once::new();
+
+/// How long `whisper_stop` waits for the running mux/accept-loop task to unwind after
+/// cancellation before giving up and reporting failure.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+/// The FFI surface is called repeatedly (init/start/stop/init again) by the embedding
+/// host, so it needs one `Runtime` that outlives any single call — `whisper_start`
+/// spawns a background task that must keep running after the call returns.
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| Runtime::new().expect("failed to create tokio runtime"))
+}
+
+static CLIENT_KEYPAIR: OnceLock<StaticKeypair> = OnceLock::new();
+
+/// The client's Noise static keypair, generated once per process the first time it's
+/// needed (either by `whisper_init` with a `server_key` set, or by an explicit
+/// `whisper_get_client_pubkey` call made before init so a host can register it up front).
+fn client_keypair() -> &'static StaticKeypair {
+    CLIENT_KEYPAIR.get_or_init(|| crypto::generate_keypair().expect("failed to generate client Noise keypair"))
+}
+
 struct WhisperInitState {
     mux: WhisperMux,
     tun: AsyncDevice,
     mtu: u16,
     socketaddr: SocketAddr,
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
 }
 
 struct WhisperRunningState {
     socketaddr: SocketAddr,
-    channel: UnboundedSender<WhisperEvent>,
+    cancel: CancellationToken,
+    handle: JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>,
+    registry: StreamRegistry,
+    tracker: TaskTracker,
 }
 
 static WHISPER: Mutex<(Option<WhisperInitState>, Option<WhisperRunningState>)> =
     Mutex::const_new((None, None));
 
 #[no_mangle]
-pub extern "C" fn whisper_init(fd: c_int, ws: *const c_char, mtu: c_ushort) -> bool {
+pub extern "C" fn whisper_init(
+    fd: c_int,
+    ws: *const c_char,
+    mtu: c_ushort,
+    tcp_timeout_secs: c_uint,
+    udp_timeout_secs: c_uint,
+    server_key: *const c_char,
+) -> bool {
+    LOGGER_INIT.call_once(|| {
+        // The backend passes every level through, so `log::set_max_level` alone decides the
+        // verbosity and `whisper_set_log_level` can raise it above `info` as well as lower it.
+        init_logger(LogLevel::Trace);
+        log::set_max_level(LogLevel::Info.to_level_filter());
+    });
+
     let ws = unsafe {
         if ws.is_null() {
             return false;
         }
         CStr::from_ptr(ws).to_string_lossy().to_string()
     };
-    if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
+    let server_key = unsafe {
+        if server_key.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(server_key).to_string_lossy().to_string())
+        }
+    };
+    runtime()
+        .block_on(async {
             let mut whisper = WHISPER.lock().await;
 
             if whisper.0.is_some() || whisper.1.is_some() {
                 return Err(WhisperError::AlreadyInitialized);
             }
 
-            let (mux, socketaddr) = connect_to_wisp(&WispServer {
-                pty: None,
-                url: Some(Uri::try_from(ws).map_err(WhisperError::other)?),
-            })
+            let server_public_key = server_key.as_deref().map(crypto::decode_hex).transpose().map_err(WhisperError::Other)?;
+            let encryption = server_public_key.as_deref().map(|server_public_key| EncryptionKeys {
+                client_private_key: &client_keypair().private,
+                server_public_key,
+            });
+
+            let (mux, socketaddr) = connect_to_wisp(
+                &WispServer {
+                    pty: None,
+                    url: Some(Uri::try_from(ws).map_err(WhisperError::other)?),
+                },
+                encryption.as_ref(),
+            )
             .await
             .map_err(WhisperError::Other)?;
 
@@ -67,34 +131,62 @@ pub extern "C" fn whisper_init(fd: c_int, ws: *const c_char, mtu: c_ushort) -> b
                 tun,
                 mtu,
                 socketaddr: socketaddr.ok_or(WhisperError::NoSocketAddr)?,
+                tcp_timeout: Duration::from_secs(tcp_timeout_secs as u64),
+                udp_timeout: Duration::from_secs(udp_timeout_secs as u64),
             });
             Ok(())
         })
         .is_ok()
-    } else {
-        false
-    }
 }
 
 #[no_mangle]
 pub extern "C" fn whisper_get_ws_ip() -> *mut c_char {
-    if let Ok(rt) = Runtime::new() {
-        let ip = rt.block_on(async {
-            let whisper = WHISPER.lock().await;
-            if let Some(init) = &whisper.0 {
-                CString::new(init.socketaddr.to_string()).map_err(WhisperError::other)
-            } else if let Some(running) = &whisper.1 {
-                CString::new(running.socketaddr.to_string()).map_err(WhisperError::other)
-            } else {
-                Err(WhisperError::NotInitialized)
-            }
-        });
-        match ip {
-            Ok(ptr) => ptr.into_raw(),
-            Err(_) => ptr::null_mut(),
+    let ip = runtime().block_on(async {
+        let whisper = WHISPER.lock().await;
+        if let Some(init) = &whisper.0 {
+            CString::new(init.socketaddr.to_string()).map_err(WhisperError::other)
+        } else if let Some(running) = &whisper.1 {
+            CString::new(running.socketaddr.to_string()).map_err(WhisperError::other)
+        } else {
+            Err(WhisperError::NotInitialized)
         }
-    } else {
-        ptr::null_mut()
+    });
+    match ip {
+        Ok(ptr) => ptr.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Lets a host that persists the client's Noise static private key itself (e.g. in its own
+/// secure storage) restore that long-lived identity instead of a fresh one being generated
+/// every process launch, which a server authorizing this client by static key depends on.
+/// Must be called before the keypair is first touched by `whisper_get_client_pubkey` or
+/// `whisper_init`; returns `false` if that already happened, or if `hex` doesn't parse.
+#[no_mangle]
+pub extern "C" fn whisper_set_client_private_key(hex: *const c_char) -> bool {
+    let hex = unsafe {
+        if hex.is_null() {
+            return false;
+        }
+        CStr::from_ptr(hex).to_string_lossy().to_string()
+    };
+    let Ok(private) = crypto::decode_hex(&hex) else {
+        return false;
+    };
+    let Ok(keypair) = crypto::keypair_from_private(private) else {
+        return false;
+    };
+    CLIENT_KEYPAIR.set(keypair).is_ok()
+}
+
+/// Returns this process's Noise static public key as lowercase hex, generating it on
+/// first use if necessary, so a host can register it with a server before ever calling
+/// `whisper_init` with a `server_key`. Freed via [`whisper_free`].
+#[no_mangle]
+pub extern "C" fn whisper_get_client_pubkey() -> *mut c_char {
+    match CString::new(crypto::encode_hex(&client_keypair().public)) {
+        Ok(ptr) => ptr.into_raw(),
+        Err(_) => ptr::null_mut(),
     }
 }
 
@@ -110,8 +202,8 @@ pub extern "C" fn whisper_free(s: *mut c_char) {
 
 #[no_mangle]
 pub extern "C" fn whisper_start() -> bool {
-    if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
+    runtime()
+        .block_on(async {
             let mut whisper = WHISPER.lock().await;
             if whisper.1.is_some() {
                 return Err(WhisperError::AlreadyStarted);
@@ -121,39 +213,93 @@ pub extern "C" fn whisper_start() -> bool {
                 tun,
                 mtu,
                 socketaddr,
+                tcp_timeout,
+                udp_timeout,
             } = whisper.0.take().ok_or(WhisperError::NotInitialized)?;
-            let (channel, rx) = unbounded_channel();
+            let cancel = CancellationToken::new();
+            let registry = StreamRegistry::new();
+            let tracker = TaskTracker::new();
+            let handle = tokio::spawn(start_whisper(
+                mux,
+                tun,
+                mtu,
+                tcp_timeout,
+                udp_timeout,
+                registry.clone(),
+                tracker.clone(),
+                cancel.clone(),
+            ));
             whisper.1.replace(WhisperRunningState {
-                channel,
                 socketaddr,
+                cancel,
+                handle,
+                registry,
+                tracker,
             });
-            start_whisper(mux, tun, mtu, rx)
-                .await
-                .map_err(WhisperError::Other)
+            Ok(())
         })
         .is_ok()
-    } else {
-        false
+}
+
+/// Lets Android/iOS hosts crank the `log` verbosity at runtime, independent of whatever
+/// level [`whisper_init`] set up the `env_logger` backend with.
+#[no_mangle]
+pub extern "C" fn whisper_set_log_level(level: c_int) -> bool {
+    match LogLevel::try_from(level as i32) {
+        Ok(level) => {
+            log::set_max_level(level.to_level_filter());
+            true
+        }
+        Err(()) => false,
     }
 }
 
+#[no_mangle]
+pub extern "C" fn whisper_stats() -> *mut c_char {
+    let json = runtime().block_on(async {
+        let whisper = WHISPER.lock().await;
+        let running = whisper.1.as_ref().ok_or(WhisperError::NotStarted)?;
+        CString::new(running.registry.to_json().await).map_err(WhisperError::other)
+    });
+    match json {
+        Ok(json) => json.into_raw(),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Cancels the running mux/accept-loop task and waits up to [`STOP_TIMEOUT`] for it, and
+/// every in-flight TCP/UDP forwarding task it spawned, to unwind gracefully before
+/// returning. The process stays alive and ready for another `whisper_init`/`whisper_start`
+/// cycle either way.
 #[no_mangle]
 pub extern "C" fn whisper_stop() -> bool {
-    if let Ok(rt) = Runtime::new() {
-        rt.block_on(async {
+    runtime()
+        .block_on(async {
             let mut whisper = WHISPER.lock().await;
             if whisper.1.is_none() {
                 return Err(WhisperError::NotStarted);
             }
-            let WhisperRunningState { channel, .. } =
+            let WhisperRunningState { cancel, handle, tracker, .. } =
                 whisper.1.take().ok_or(WhisperError::NotInitialized)?;
-            channel
-                .send(WhisperEvent::EndFut)
-                .map_err(WhisperError::other)?;
-            Ok(())
+            cancel.cancel();
+            tokio::time::timeout(STOP_TIMEOUT, async {
+                let result = handle.await;
+                tracker.close();
+                tracker.wait().await;
+                result
+            })
+            .await
+            .map_err(|_| {
+                WhisperError::other(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out waiting for whisper to stop",
+                ))
+            })
+            .and_then(|joined| match joined {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(err)) => Err(WhisperError::Other(err)),
+                Err(err) => Err(WhisperError::other(err)),
+            })
         })
         .is_ok()
-    } else {
-        false
-    }
-}
\ No newline at end of file
+}
@@ -0,0 +1,249 @@
+use std::{
+    error::Error,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use futures_util::future::try_join_all;
+use log::{debug, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
+use wisp_mux::{ClientMux, StreamType};
+
+use crate::{
+    copy_with_idle_timeout,
+    stats::StreamRegistry,
+};
+
+/// A parsed `[tcp|udp:]LOCAL_PORT:HOST:PORT` port-forward spec, as passed to `--forward`.
+#[derive(Clone)]
+pub(crate) struct ForwardSpec {
+    protocol: StreamType,
+    local_port: u16,
+    target_host: String,
+    target_port: u16,
+}
+
+impl FromStr for ForwardSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid forward spec {s:?}, expected [tcp|udp:]LOCAL_PORT:HOST:PORT");
+
+        let parts: Vec<&str> = s.split(':').collect();
+        let (protocol, rest) = match parts.as_slice() {
+            [proto @ ("tcp" | "udp"), local, host, port] => {
+                let protocol = if *proto == "tcp" { StreamType::Tcp } else { StreamType::Udp };
+                (protocol, [*local, *host, *port])
+            }
+            [local, host, port] => (StreamType::Tcp, [*local, *host, *port]),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            protocol,
+            local_port: rest[0].parse().map_err(|_| invalid())?,
+            target_host: rest[1].to_string(),
+            target_port: rest[2].parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Runs every `--forward` listener concurrently until one of them hits a fatal I/O error
+/// or `cancel` fires.
+pub(crate) async fn run_forwards<R, W>(
+    mux: &ClientMux<R, W>,
+    specs: &[ForwardSpec],
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
+    registry: &StreamRegistry,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    try_join_all(specs.iter().map(|spec| {
+        let cancel = cancel.clone();
+        async move {
+            let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, spec.local_port));
+            match spec.protocol {
+                StreamType::Tcp => run_tcp_forward(mux, local_addr, spec, tcp_timeout, registry, cancel).await,
+                StreamType::Udp => run_udp_forward(mux, local_addr, spec, udp_timeout, registry, cancel).await,
+                _ => Ok(()),
+            }
+        }
+    }))
+    .await?;
+    Ok(())
+}
+
+async fn run_tcp_forward<R, W>(
+    mux: &ClientMux<R, W>,
+    local_addr: SocketAddr,
+    spec: &ForwardSpec,
+    idle_timeout: Duration,
+    registry: &StreamRegistry,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let listener = TcpListener::bind(local_addr).await?;
+    info!("Forwarding tcp {} -> {}:{}", local_addr, spec.target_host, spec.target_port);
+
+    loop {
+        let (conn, peer) = tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = listener.accept() => accepted?,
+        };
+        debug!("Accepted local TCP connection from {peer}, opening Wisp stream");
+        let stream = mux
+            .client_new_stream(StreamType::Tcp, spec.target_host.clone(), spec.target_port)
+            .await?
+            .into_io()
+            .into_asyncrw();
+        let (id, entry) = registry.register(peer, StreamType::Tcp).await;
+        let registry = registry.clone();
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if let Err(err) = copy_with_idle_timeout(conn, stream, idle_timeout, &entry, cancel).await {
+                warn!("Error while forwarding TCP stream from {peer}: {err}");
+            }
+            debug!(
+                "Closed TCP stream from {peer} (up: {} bytes, down: {} bytes)",
+                entry.bytes_up.load(Ordering::Relaxed),
+                entry.bytes_down.load(Ordering::Relaxed)
+            );
+            registry.remove(id).await;
+        });
+    }
+}
+
+async fn run_udp_forward<R, W>(
+    mux: &ClientMux<R, W>,
+    local_addr: SocketAddr,
+    spec: &ForwardSpec,
+    idle_timeout: Duration,
+    registry: &StreamRegistry,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let socket = Arc::new(UdpSocket::bind(local_addr).await?);
+    info!("Forwarding udp {} -> {}:{}", local_addr, spec.target_host, spec.target_port);
+
+    // UDP is connectionless, so demux inbound datagrams by source peer into one Wisp
+    // stream per peer, fed through a small AsyncRead/AsyncWrite adapter over the shared
+    // listening socket. Forwarding tasks report back over `done` when they finish (e.g.
+    // via the idle timeout) so their `peers` entry is reaped instead of leaking forever.
+    let mut peers: AHashMap<SocketAddr, mpsc::UnboundedSender<Vec<u8>>> = AHashMap::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut buf = [0u8; 65536];
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            Some(done_peer) = done_rx.recv() => {
+                peers.remove(&done_peer);
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (n, peer) = received?;
+                if let Some(tx) = peers.get(&peer) {
+                    if tx.send(buf[..n].to_vec()).is_ok() {
+                        continue;
+                    }
+                    peers.remove(&peer);
+                }
+
+                debug!("Accepted local UDP flow from {peer}, opening Wisp stream");
+                let (tx, rx) = mpsc::unbounded_channel();
+                let _ = tx.send(buf[..n].to_vec());
+                let stream = mux
+                    .client_new_stream(StreamType::Udp, spec.target_host.clone(), spec.target_port)
+                    .await?
+                    .into_io()
+                    .into_asyncrw();
+                let (id, entry) = registry.register(peer, StreamType::Udp).await;
+                let registry = registry.clone();
+                let io = UdpPeerIo {
+                    socket: socket.clone(),
+                    peer,
+                    inbound: rx,
+                    pending: None,
+                };
+                let cancel = cancel.clone();
+                let done_tx = done_tx.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = copy_with_idle_timeout(io, stream, idle_timeout, &entry, cancel).await {
+                        warn!("Error while forwarding UDP datagrams from {peer}: {err}");
+                    }
+                    debug!(
+                        "Closed UDP flow from {peer} (up: {} bytes, down: {} bytes)",
+                        entry.bytes_up.load(Ordering::Relaxed),
+                        entry.bytes_down.load(Ordering::Relaxed)
+                    );
+                    registry.remove(id).await;
+                    let _ = done_tx.send(peer);
+                });
+                peers.insert(peer, tx);
+            }
+        }
+    }
+}
+
+/// Adapts a single peer's slice of a shared [`UdpSocket`] to `AsyncRead`/`AsyncWrite`:
+/// reads drain datagrams handed to it by the listener's demux loop, writes go straight
+/// back out the shared socket addressed to `peer`.
+struct UdpPeerIo {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Option<Vec<u8>>,
+}
+
+impl AsyncRead for UdpPeerIo {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if let Some(pending) = self.pending.take() {
+                let n = pending.len().min(buf.remaining());
+                buf.put_slice(&pending[..n]);
+                if n < pending.len() {
+                    self.pending = Some(pending[n..].to_vec());
+                }
+                return Poll::Ready(Ok(()));
+            }
+            match self.inbound.poll_recv(cx) {
+                Poll::Ready(Some(datagram)) => self.pending = Some(datagram),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UdpPeerIo {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, self.peer)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,91 +1,380 @@
+mod crypto;
+mod ffi;
+mod forward;
 mod pty;
+mod stats;
+mod util;
 
-use std::{error::Error, net::Ipv4Addr, process::abort};
+use std::{error::Error, net::Ipv4Addr, net::SocketAddr, path::Path, sync::atomic::Ordering, time::Duration};
 
-use clap::Parser;
-use ipstack::{IpStack, IpStackConfig};
-use tokio::io::copy_bidirectional;
-use tun2::{create_as_async, Configuration};
+use clap::{Parser, Subcommand, ValueEnum};
+use hyper::Uri;
+use ipstack::{stream::IpStackStream as S, IpStack, IpStackConfig};
+use log::{debug, info, warn};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep_until, Instant},
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tun2::{create_as_async, AsyncDevice, Configuration};
 use wisp_mux::{ClientMux, StreamType};
 
-/// Implementation of Wisp over a pty. Exposes the Wisp connection over a TUN device.
+use crate::{
+    crypto::EncryptionKeys,
+    forward::ForwardSpec,
+    stats::{StreamRegistry, StreamStats},
+    util::{connect_to_wisp, WhisperMux},
+};
+
+const STATS_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+const DEFAULT_TCP_TIMEOUT: u64 = 60;
+const DEFAULT_UDP_TIMEOUT: u64 = 10;
+
+/// Describes how to reach the Wisp server: either a local PTY device or a WebSocket URL.
+pub(crate) struct WispServer {
+    pub pty: Option<String>,
+    pub url: Option<Uri>,
+}
+
+/// Verbosity for the `log` facade, from `-v/--verbosity`. Defaults to `info`.
+#[derive(Copy, Clone, ValueEnum)]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub(crate) fn to_level_filter(self) -> log::LevelFilter {
+        match self {
+            Self::Error => log::LevelFilter::Error,
+            Self::Warn => log::LevelFilter::Warn,
+            Self::Info => log::LevelFilter::Info,
+            Self::Debug => log::LevelFilter::Debug,
+            Self::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+impl TryFrom<i32> for LogLevel {
+    type Error = ();
+
+    fn try_from(level: i32) -> Result<Self, ()> {
+        match level {
+            0 => Ok(Self::Error),
+            1 => Ok(Self::Warn),
+            2 => Ok(Self::Info),
+            3 => Ok(Self::Debug),
+            4 => Ok(Self::Trace),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Initializes the `log` facade with an `env_logger` backend at the given level.
+pub(crate) fn init_logger(level: LogLevel) {
+    env_logger::Builder::new().filter_level(level.to_level_filter()).init();
+}
+
+/// Implementation of Wisp over a pty, either exposed over a TUN device or as a plain
+/// local port forwarder.
 #[derive(Parser)]
 #[command(version = clap::crate_version!())]
 struct Cli {
     /// Path to PTY device
     #[arg(short, long)]
     pty: String,
-    /// Name of created TUN device
-    #[arg(short, long)]
-    tun: String,
-    /// MTU of created TUN device
-    #[arg(short, long, default_value_t = u16::MAX)]
-    mtu: u16,
+    /// Idle timeout in seconds before an inactive TCP forward is torn down
+    #[arg(long, default_value_t = DEFAULT_TCP_TIMEOUT)]
+    tcp_timeout: u64,
+    /// Idle timeout in seconds before an inactive UDP forward is torn down
+    #[arg(long, default_value_t = DEFAULT_UDP_TIMEOUT)]
+    udp_timeout: u64,
+    /// Log verbosity
+    #[arg(short = 'v', long, value_enum, default_value = "info")]
+    verbosity: LogLevel,
+    /// Hex-encoded Noise static public key of the Wisp server; when set, the Wisp
+    /// transport is wrapped in an authenticated, encrypted Noise_IK session
+    #[arg(long)]
+    server_key: Option<String>,
+    /// Path to this client's persisted Noise static private key, used with `--server-key`;
+    /// generated and saved there on first run so the server can authorize a stable identity
+    /// across restarts instead of a fresh one every time
+    #[arg(long, requires = "server_key")]
+    client_key_file: Option<String>,
+    #[command(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Expose the Wisp connection over a TUN device
+    Tun {
+        /// Name of created TUN device
+        #[arg(short, long)]
+        tun: String,
+        /// MTU of created TUN device
+        #[arg(short, long, default_value_t = u16::MAX)]
+        mtu: u16,
+    },
+    /// Tunnel local TCP/UDP listeners over the Wisp mux without a TUN device or root
+    Forward {
+        /// `[tcp|udp:]LOCAL_PORT:HOST:PORT`, may be repeated
+        #[arg(long = "forward", required = true)]
+        forwards: Vec<ForwardSpec>,
+    },
 }
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<(), Box<dyn Error + 'static>> {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     let opts = Cli::parse();
+    init_logger(opts.verbosity);
 
-    println!("Connecting to PTY: {:?}", opts.pty);
-    let (rx, tx) = pty::open_pty(opts.pty).await?;
-    let (mux, fut) = ClientMux::new(rx, tx).await?;
+    info!("Connecting to PTY: {:?}", opts.pty);
 
-    tokio::spawn(async move {
-        if let Err(err) = fut.await {
-            eprintln!("Error in Wisp multiplexor future: {}", err);
-            abort();
+    let server_public_key = opts.server_key.as_deref().map(crypto::decode_hex).transpose()?;
+    let keypair = match (&server_public_key, &opts.client_key_file) {
+        (Some(_), Some(key_file)) => Some(crypto::load_or_generate_keypair(Path::new(key_file)).await?),
+        (Some(_), None) => Some(crypto::generate_keypair()?),
+        (None, _) => None,
+    };
+    if let Some(keypair) = &keypair {
+        info!("Client Noise static public key: {}", crypto::encode_hex(&keypair.public));
+    }
+    let encryption = keypair.as_ref().zip(server_public_key.as_deref()).map(|(keypair, server_public_key)| {
+        EncryptionKeys {
+            client_private_key: &keypair.private,
+            server_public_key,
         }
     });
 
-    println!("Creating TUN device with name: {:?}", opts.tun);
-    let tun = create_as_async(
-        Configuration::default()
-            .address(Ipv4Addr::new(10, 0, 10, 2))
-            .netmask(Ipv4Addr::new(255, 255, 255, 0))
-            .destination(Ipv4Addr::new(10, 0, 10, 1))
-            .platform_config(|c| {
-                c.ensure_root_privileges(true);
-            })
-            .mtu(opts.mtu)
-            .tun_name(opts.tun)
-            .up(),
-    )?;
+    let (mux, _): (WhisperMux, _) = connect_to_wisp(&WispServer { pty: Some(opts.pty), url: None }, encryption.as_ref()).await?;
+
+    let cancel = CancellationToken::new();
+    {
+        let cancel = cancel.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                info!("Received Ctrl-C, shutting down");
+                cancel.cancel();
+            }
+        });
+    }
+
+    let tcp_timeout = Duration::from_secs(opts.tcp_timeout);
+    let udp_timeout = Duration::from_secs(opts.udp_timeout);
+
+    let registry = StreamRegistry::new();
+    tokio::spawn(log_stats_periodically(registry.clone()));
+    let tracker = TaskTracker::new();
+
+    match opts.mode {
+        Mode::Tun { tun, mtu } => {
+            info!("Creating TUN device with name: {:?}", tun);
+            let tun = create_as_async(
+                Configuration::default()
+                    .address(Ipv4Addr::new(10, 0, 10, 2))
+                    .netmask(Ipv4Addr::new(255, 255, 255, 0))
+                    .destination(Ipv4Addr::new(10, 0, 10, 1))
+                    .platform_config(|c| {
+                        c.ensure_root_privileges(true);
+                    })
+                    .mtu(mtu)
+                    .tun_name(tun)
+                    .up(),
+            )?;
 
+            start_whisper(mux, tun, mtu, tcp_timeout, udp_timeout, registry, tracker, cancel).await
+        }
+        Mode::Forward { forwards } => {
+            forward::run_forwards(&mux, &forwards, tcp_timeout, udp_timeout, &registry, cancel).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Logs the number of active forwards and their byte counters every [`STATS_LOG_INTERVAL`].
+async fn log_stats_periodically(registry: StreamRegistry) {
+    let mut ticker = tokio::time::interval(STATS_LOG_INTERVAL);
+    loop {
+        ticker.tick().await;
+        info!(
+            "{} active stream(s): {}",
+            registry.len().await,
+            registry.to_json().await
+        );
+    }
+}
+
+/// Drives the Wisp accept loop for an already-established mux and TUN device, tearing
+/// down each forward after `tcp_timeout`/`udp_timeout` of inactivity. Used by the FFI
+/// layer, which asks the loop (and every in-flight forward) to stop by cancelling `cancel`,
+/// then waits for `tracker` to drain so `whisper_stop` only returns once every forwarding
+/// task spawned here has actually finished.
+pub(crate) async fn start_whisper(
+    mux: WhisperMux,
+    tun: AsyncDevice,
+    mtu: u16,
+    tcp_timeout: Duration,
+    udp_timeout: Duration,
+    registry: StreamRegistry,
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut ip_stack_config = IpStackConfig::default();
-    ip_stack_config.mtu(opts.mtu);
+    ip_stack_config.mtu(mtu);
     let mut ip_stack = IpStack::new(ip_stack_config, tun);
 
     loop {
-        use ipstack::stream::IpStackStream as S;
-        match ip_stack.accept().await? {
-            S::Tcp(mut tcp) => {
-                let addr = tcp.peer_addr();
-                let mut stream = mux
-                    .client_new_stream(StreamType::Tcp, addr.ip().to_string(), addr.port())
-                    .await?
-                    .into_io()
-                    .into_asyncrw();
-                tokio::spawn(async move {
-                    if let Err(err) = copy_bidirectional(&mut tcp, &mut stream).await {
-                        eprintln!("Error while forwarding TCP stream: {}", err);
-                    }
-                });
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            accepted = ip_stack.accept() => match accepted? {
+                S::Tcp(tcp) => {
+                    let addr = tcp.peer_addr();
+                    handle_tcp(&mux, addr, tcp, tcp_timeout, &registry, &tracker, cancel.clone()).await?
+                }
+                S::Udp(udp) => {
+                    let addr = udp.peer_addr();
+                    handle_udp(&mux, addr, udp, udp_timeout, &registry, &tracker, cancel.clone()).await?
+                }
+                _ => {}
+            },
+        }
+    }
+}
+
+async fn handle_tcp<R, W>(
+    mux: &ClientMux<R, W>,
+    addr: SocketAddr,
+    tcp: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    idle_timeout: Duration,
+    registry: &StreamRegistry,
+    tracker: &TaskTracker,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    debug!("Accepted TCP connection from {addr}, opening Wisp stream");
+    let stream = mux
+        .client_new_stream(StreamType::Tcp, addr.ip().to_string(), addr.port())
+        .await?
+        .into_io()
+        .into_asyncrw();
+    let (id, entry) = registry.register(addr, StreamType::Tcp).await;
+    let registry = registry.clone();
+    tracker.spawn(async move {
+        if let Err(err) = copy_with_idle_timeout(tcp, stream, idle_timeout, &entry, cancel).await {
+            warn!("Error while forwarding TCP stream from {addr}: {err}");
+        }
+        debug!(
+            "Closed TCP stream from {addr} (up: {} bytes, down: {} bytes)",
+            entry.bytes_up.load(Ordering::Relaxed),
+            entry.bytes_down.load(Ordering::Relaxed)
+        );
+        registry.remove(id).await;
+    });
+    Ok(())
+}
+
+async fn handle_udp<R, W>(
+    mux: &ClientMux<R, W>,
+    addr: SocketAddr,
+    udp: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    idle_timeout: Duration,
+    registry: &StreamRegistry,
+    tracker: &TaskTracker,
+    cancel: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    debug!("Accepted UDP flow from {addr}, opening Wisp stream");
+    let stream = mux
+        .client_new_stream(StreamType::Udp, addr.ip().to_string(), addr.port())
+        .await?
+        .into_io()
+        .into_asyncrw();
+    let (id, entry) = registry.register(addr, StreamType::Udp).await;
+    let registry = registry.clone();
+    tracker.spawn(async move {
+        if let Err(err) = copy_with_idle_timeout(udp, stream, idle_timeout, &entry, cancel).await {
+            warn!("Error while forwarding UDP datagrams from {addr}: {err}");
+        }
+        debug!(
+            "Closed UDP flow from {addr} (up: {} bytes, down: {} bytes)",
+            entry.bytes_up.load(Ordering::Relaxed),
+            entry.bytes_down.load(Ordering::Relaxed)
+        );
+        registry.remove(id).await;
+    });
+    Ok(())
+}
+
+/// Copies bytes in both directions between `a` and `b` until both directions have reached
+/// EOF, either side errors, `cancel` fires, or `idle_timeout` elapses without activity in
+/// either direction. A one-sided EOF only shuts down that direction's writer and keeps
+/// relaying the other (TCP half-close passthrough), matching `tokio::io::copy_bidirectional`.
+/// Implemented manually (rather than via `copy_bidirectional`) so the deadline can be reset
+/// on every byte moved in either direction, which `copy_bidirectional` has no hook for.
+pub(crate) async fn copy_with_idle_timeout<A, B>(
+    a: A,
+    b: B,
+    idle_timeout: Duration,
+    stats: &StreamStats,
+    cancel: CancellationToken,
+) -> std::io::Result<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_rd, mut a_wr) = split(a);
+    let (mut b_rd, mut b_wr) = split(b);
+
+    let deadline = sleep_until(Instant::now() + idle_timeout);
+    tokio::pin!(deadline);
+
+    let mut a_to_b = [0u8; 8192];
+    let mut b_to_a = [0u8; 8192];
+
+    let mut a_to_b_done = false;
+    let mut b_to_a_done = false;
+
+    loop {
+        if a_to_b_done && b_to_a_done {
+            return Ok(());
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return Ok(()),
+            _ = &mut deadline => return Ok(()),
+            n = a_rd.read(&mut a_to_b), if !a_to_b_done => {
+                let n = n?;
+                if n == 0 {
+                    b_wr.shutdown().await?;
+                    a_to_b_done = true;
+                    continue;
+                }
+                b_wr.write_all(&a_to_b[..n]).await?;
+                stats.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+                deadline.as_mut().reset(Instant::now() + idle_timeout);
             }
-            S::Udp(mut udp) => {
-                let addr = udp.peer_addr();
-                let mut stream = mux
-                    .client_new_stream(StreamType::Udp, addr.ip().to_string(), addr.port())
-                    .await?
-                    .into_io()
-                    .into_asyncrw();
-                tokio::spawn(async move {
-                    if let Err(err) = copy_bidirectional(&mut udp, &mut stream).await {
-                        eprintln!("Error while forwarding UDP datagrams: {}", err);
-                    }
-                });
+            n = b_rd.read(&mut b_to_a), if !b_to_a_done => {
+                let n = n?;
+                if n == 0 {
+                    a_wr.shutdown().await?;
+                    b_to_a_done = true;
+                    continue;
+                }
+                a_wr.write_all(&b_to_a[..n]).await?;
+                stats.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+                deadline.as_mut().reset(Instant::now() + idle_timeout);
             }
-            _ => {}
         }
     }
 }
@@ -0,0 +1,13 @@
+use std::io;
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{split, ReadHalf, WriteHalf},
+};
+
+/// Opens `path` as a PTY device and splits it into the read/write halves expected by
+/// [`wisp_mux::ClientMux::new`].
+pub async fn open_pty(path: String) -> io::Result<(ReadHalf<File>, WriteHalf<File>)> {
+    let file = OpenOptions::new().read(true).write(true).open(path).await?;
+    Ok(split(file))
+}
@@ -0,0 +1,98 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ahash::AHashMap;
+use tokio::sync::Mutex;
+use wisp_mux::StreamType;
+
+pub type StreamId = u64;
+
+/// Live statistics for a single forwarded TCP/UDP stream.
+pub struct StreamStats {
+    pub peer: SocketAddr,
+    pub stream_type: StreamType,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub started_at: SystemTime,
+}
+
+impl StreamStats {
+    fn new(peer: SocketAddr, stream_type: StreamType) -> Self {
+        Self {
+            peer,
+            stream_type,
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            started_at: SystemTime::now(),
+        }
+    }
+
+    fn stream_type_str(&self) -> &'static str {
+        match self.stream_type {
+            StreamType::Tcp => "tcp",
+            StreamType::Udp => "udp",
+            _ => "unknown",
+        }
+    }
+
+    fn to_json(&self, id: StreamId) -> String {
+        let started_at = self
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!(
+            r#"{{"id":{},"peer":"{}","type":"{}","bytes_up":{},"bytes_down":{},"started_at":{}}}"#,
+            id,
+            self.peer,
+            self.stream_type_str(),
+            self.bytes_up.load(Ordering::Relaxed),
+            self.bytes_down.load(Ordering::Relaxed),
+            started_at,
+        )
+    }
+}
+
+/// Shared table of every currently-forwarding stream, keyed by a monotonically
+/// increasing id. The accept loop registers a stream before spawning its forwarding
+/// task and removes it once that task completes.
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    next_id: Arc<AtomicU64>,
+    streams: Arc<Mutex<AHashMap<StreamId, Arc<StreamStats>>>>,
+}
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn register(&self, peer: SocketAddr, stream_type: StreamType) -> (StreamId, Arc<StreamStats>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(StreamStats::new(peer, stream_type));
+        self.streams.lock().await.insert(id, stats.clone());
+        (id, stats)
+    }
+
+    pub async fn remove(&self, id: StreamId) {
+        self.streams.lock().await.remove(&id);
+    }
+
+    pub async fn len(&self) -> usize {
+        self.streams.lock().await.len()
+    }
+
+    /// Serializes every live stream's statistics to a JSON array, e.g. for
+    /// `whisper_stats()` or a periodic log line.
+    pub async fn to_json(&self) -> String {
+        let streams = self.streams.lock().await;
+        let entries: Vec<String> = streams.iter().map(|(id, stats)| stats.to_json(*id)).collect();
+        format!("[{}]", entries.join(","))
+    }
+}
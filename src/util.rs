@@ -0,0 +1,132 @@
+use std::{error::Error, fmt, net::SocketAddr};
+
+use futures_util::{SinkExt, StreamExt};
+use log::error;
+use tokio::io::{join, split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use wisp_mux::ClientMux;
+
+use crate::{
+    crypto::{EncryptedStream, EncryptionKeys},
+    pty, WispServer,
+};
+
+/// A Wisp mux whose transport has been type-erased so it can be built from either a PTY
+/// or a WebSocket connection.
+pub type WhisperMux = ClientMux<Box<dyn AsyncRead + Unpin + Send>, Box<dyn AsyncWrite + Unpin + Send>>;
+
+#[derive(Debug)]
+pub enum WhisperError {
+    AlreadyInitialized,
+    AlreadyStarted,
+    NotInitialized,
+    NotStarted,
+    NoSocketAddr,
+    Other(Box<dyn Error + Send + Sync>),
+}
+
+impl WhisperError {
+    pub fn other<E: Error + Send + Sync + 'static>(err: E) -> Self {
+        Self::Other(Box::new(err))
+    }
+}
+
+impl fmt::Display for WhisperError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyInitialized => write!(f, "whisper is already initialized"),
+            Self::AlreadyStarted => write!(f, "whisper is already started"),
+            Self::NotInitialized => write!(f, "whisper has not been initialized"),
+            Self::NotStarted => write!(f, "whisper has not been started"),
+            Self::NoSocketAddr => write!(f, "could not resolve a socket address for the wisp server"),
+            Self::Other(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for WhisperError {}
+
+/// Connects to the Wisp server described by `server`, returning the established mux and,
+/// if known, the resolved socket address of the remote end. When `encryption` is set, the
+/// transport is wrapped in a Noise_IK [`EncryptedStream`] before the mux is built on top
+/// of it, so every Wisp frame is authenticated and encrypted end-to-end.
+pub async fn connect_to_wisp(
+    server: &WispServer,
+    encryption: Option<&EncryptionKeys<'_>>,
+) -> Result<(WhisperMux, Option<SocketAddr>), Box<dyn Error + Send + Sync>> {
+    if let Some(path) = &server.pty {
+        let (rx, tx) = pty::open_pty(path.clone()).await?;
+        let (mux, fut) = if let Some(keys) = encryption {
+            let encrypted = EncryptedStream::handshake(join(rx, tx), keys).await?;
+            let (erx, etx) = split(encrypted);
+            ClientMux::new(Box::new(erx) as _, Box::new(etx) as _).await?
+        } else {
+            ClientMux::new(Box::new(rx) as _, Box::new(tx) as _).await?
+        };
+        tokio::spawn(async move {
+            if let Err(err) = fut.await {
+                error!("Error in Wisp multiplexor future over PTY: {}", err);
+            }
+        });
+        return Ok((mux, None));
+    }
+
+    let url = server.url.as_ref().ok_or(WhisperError::NoSocketAddr)?;
+    let host = url.host().ok_or(WhisperError::NoSocketAddr)?;
+    let port = url
+        .port_u16()
+        .unwrap_or(if url.scheme_str() == Some("wss") { 443 } else { 80 });
+    let socketaddr = tokio::net::lookup_host((host, port)).await?.next();
+
+    let (ws, _) = connect_async(url.to_string()).await?;
+    let (mut ws_tx, mut ws_rx) = ws.split();
+
+    // Bridge the WebSocket frames onto a plain duplex pipe so `ClientMux` can talk to it
+    // like any other `AsyncRead`/`AsyncWrite` transport.
+    let (near, far) = tokio::io::duplex(64 * 1024);
+    let (mut far_rd, mut far_wr) = split(far);
+    tokio::spawn(async move {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            tokio::select! {
+                read = far_rd.read(&mut buf) => {
+                    match read {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if ws_tx.send(Message::Binary(buf[..n].to_vec())).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                msg = ws_rx.next() => {
+                    match msg {
+                        Some(Ok(Message::Binary(data))) => {
+                            if far_wr.write_all(&data).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(_)) => {}
+                        _ => break,
+                    }
+                }
+            }
+        }
+    });
+
+    let (rx, tx) = split(near);
+    let (mux, fut) = if let Some(keys) = encryption {
+        let encrypted = EncryptedStream::handshake(join(rx, tx), keys).await?;
+        let (erx, etx) = split(encrypted);
+        ClientMux::new(Box::new(erx) as _, Box::new(etx) as _).await?
+    } else {
+        ClientMux::new(Box::new(rx) as _, Box::new(tx) as _).await?
+    };
+    tokio::spawn(async move {
+        if let Err(err) = fut.await {
+            error!("Error in Wisp multiplexor future over WebSocket: {}", err);
+        }
+    });
+
+    Ok((mux, socketaddr))
+}